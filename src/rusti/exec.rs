@@ -9,14 +9,21 @@
 //! Rust code parsing and compilation.
 
 use std::any::Any;
+use std::cmp;
+use std::env;
 use std::ffi::{CStr, CString};
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::rc::Rc;
 use std::str::from_utf8;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread::Builder;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use rustc;
 use rustc_lint;
@@ -25,16 +32,22 @@ use rustc::dep_graph::DepGraph;
 use rustc::hir::map as ast_map;
 use rustc_llvm as llvm;
 use rustc::middle::cstore::LinkagePreference::RequireDynamic;
+use rustc::session::Session;
 use rustc::ty;
-use rustc::session::build_session;
-use rustc::session::config::{self, basic_options, build_configuration,
+use rustc::session::{build_session, build_session_with_file_loader};
+use rustc::session::config::{self, basic_options, build_configuration, parse_cfgspecs,
     ErrorOutputType, Input, Options, OptLevel};
 use rustc_driver::driver;
 use rustc_metadata::cstore::CStore;
 use rustc_resolve::MakeGlobMap;
 
 use syntax::ast::Crate;
-use syntax::codemap::MultiSpan;
+// Reuses rustc's own `syntax::codemap::FileLoader` as the plugin point for
+// `ExecutionEngine`'s pluggable source loading, rather than defining a new
+// rusti-local trait, so that `build_session_with_file_loader` can take it
+// directly. Implementors therefore need `abs_path` as well as
+// `file_exists`/`read_file`.
+use syntax::codemap::{FileLoader, MultiSpan};
 use syntax::errors;
 use syntax::errors::emitter::EmitterWriter;
 use syntax::errors::snippet::FormatMode;
@@ -48,6 +61,88 @@ pub struct ExecutionEngine {
     /// Additional search paths for libraries
     lib_paths: Vec<String>,
     sysroot: PathBuf,
+    /// Loads source for `Input::File` and any `mod`/`include!` paths via
+    /// rustc's own `syntax::codemap::FileLoader` trait; defaults to
+    /// reading from the real filesystem.
+    file_loader: Option<Arc<FileLoader + Send + Sync>>,
+    /// Callbacks invoked after each compilation phase; all `None` by
+    /// default. Shared with the worker thread so `set_controller` takes
+    /// effect on the very next compile.
+    controller: Arc<Mutex<Controller>>,
+    /// Optimization and codegen settings applied to every later compile.
+    config: CompileConfig,
+    /// Sends jobs to the persistent compilation worker thread spawned in
+    /// `new_with_config`, so every `compile` call reuses the same
+    /// `Session`/`CStore` instead of building a fresh one.
+    jobs: Sender<Job>,
+    /// Assigns each `Queries` a distinct id, so the worker knows when a
+    /// job belongs to a new snippet (and must drop its parse/expansion
+    /// cache) versus a repeat call on the same `Queries` handle.
+    next_id: AtomicUsize,
+}
+
+/// Optimization and codegen settings consumed by `build_exec_options`,
+/// mirroring the handful of `rustc::session::config::Options`/codegen
+/// fields a REPL is likely to want to flip at runtime (e.g. `:opt 3`).
+///
+/// Changes made via `ExecutionEngine::set_config` only affect compiles
+/// started afterward; a change forces the worker to rebuild its
+/// `Session` for the next compile, so prefer leaving it alone between
+/// snippets when possible.
+#[derive(Clone, PartialEq)]
+pub struct CompileConfig {
+    pub opt_level: OptLevel,
+    pub debug_assertions: bool,
+    pub target_cpu: Option<String>,
+    pub target_features: String,
+    /// Extra `--cfg` specs, e.g. `feature="foo"`.
+    pub extra_cfg: Vec<String>,
+}
+
+impl Default for CompileConfig {
+    fn default() -> CompileConfig {
+        CompileConfig{
+            opt_level: OptLevel::No,
+            debug_assertions: true,
+            target_cpu: None,
+            target_features: String::new(),
+            extra_cfg: Vec::new(),
+        }
+    }
+}
+
+/// Whether a `Controller` hook wants compilation to proceed to the next
+/// phase or stop where it is, mirroring `rustc_driver`'s `CompileController`.
+pub enum Compilation {
+    Continue,
+    Stop,
+}
+
+/// Optional callbacks invoked after parsing, after macro expansion, and
+/// after analysis, letting a REPL frontend inspect intermediate
+/// compilation artifacts (to implement `:expand`, `:check`, and similar
+/// commands) or abort before translation to LLVM.
+///
+/// Shared by every `add_module`/`with_analysis` call on the
+/// `ExecutionEngine` it is installed on, via `ExecutionEngine::set_controller`.
+#[derive(Default)]
+pub struct Controller {
+    pub after_parse: Option<Box<FnMut(&Crate) -> Compilation + Send>>,
+    pub after_expand: Option<Box<FnMut(&Crate) -> Compilation + Send>>,
+    pub after_analysis: Option<Box<for<'a, 'gcx, 'tcx>
+        FnMut(&ty::TyCtxt<'a, 'gcx, 'tcx>) -> Compilation + Send>>,
+}
+
+/// An intermediate representation of a compiled snippet that `Queries::emit`
+/// can return as text.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EmitKind {
+    /// Unoptimized LLVM IR, via `LLVMPrintModuleToString`.
+    Llvm,
+    /// Target assembly, via the LLVM codegen backend.
+    Asm,
+    /// Pretty-printed MIR for every item in the crate.
+    Mir,
 }
 
 /// A value that can be translated into `ExecutionEngine` input
@@ -88,10 +183,39 @@ impl ExecutionEngine {
     /// and library search paths.
     pub fn new_with_input<T>(input: T, libs: Vec<String>, sysroot: Option<PathBuf>) -> ExecutionEngine
             where T: IntoInput {
-        let sysroot = sysroot.unwrap_or_else(get_sysroot);
+        ExecutionEngine::new_with_loader(input, libs, sysroot, None)
+    }
+
+    /// Constructs a new `ExecutionEngine` with the given starting input,
+    /// library search paths, and source `FileLoader`.
+    ///
+    /// The given `file_loader` (if any) is used to resolve this and all
+    /// later `add_module` inputs, including the paths of any `mod` items
+    /// or `include!`s they contain, instead of reading from the real
+    /// filesystem. This lets a sandboxed or networked host serve snippets
+    /// out of an in-memory map.
+    pub fn new_with_loader<T>(input: T, libs: Vec<String>, sysroot: Option<PathBuf>,
+            file_loader: Option<Box<FileLoader + Send + Sync>>) -> ExecutionEngine
+            where T: IntoInput {
+        ExecutionEngine::new_with_config(input, libs, sysroot, file_loader, CompileConfig::default())
+    }
 
-        let (llmod, deps) = compile_input(input.into_input(),
-            sysroot.clone(), libs.clone())
+    /// Constructs a new `ExecutionEngine` with the given starting input,
+    /// library search paths, source `FileLoader`, and codegen `CompileConfig`.
+    pub fn new_with_config<T>(input: T, libs: Vec<String>, sysroot: Option<PathBuf>,
+            file_loader: Option<Box<FileLoader + Send + Sync>>,
+            config: CompileConfig) -> ExecutionEngine
+            where T: IntoInput {
+        let sysroot = sysroot.unwrap_or_else(get_sysroot);
+        let file_loader = file_loader.map(Arc::from);
+        let controller = Arc::new(Mutex::new(Controller::default()));
+        let jobs = spawn_worker(sysroot.clone(), libs.clone(),
+            file_loader.clone(), controller.clone());
+        let next_id = AtomicUsize::new(0);
+
+        let id = next_id.fetch_add(1, Ordering::SeqCst);
+        let (llmod, deps) = Queries::new(jobs.clone(), id, input.into_input(), config.clone())
+            .module()
             .expect("ExecutionEngine init input failed to compile");
 
         let ee = unsafe { llvm::LLVMBuildExecutionEngine(llmod) };
@@ -105,6 +229,11 @@ impl ExecutionEngine {
             modules: vec![llmod],
             lib_paths: libs,
             sysroot: sysroot,
+            file_loader: file_loader,
+            controller: controller,
+            config: config,
+            jobs: jobs,
+            next_id: next_id,
         };
 
         ee.load_deps(&deps);
@@ -112,6 +241,38 @@ impl ExecutionEngine {
         ee
     }
 
+    /// Replaces the optimization/codegen settings used by later compiles
+    /// (e.g. a REPL's `:opt 3`); already-added modules are unaffected.
+    pub fn set_config(&mut self, config: CompileConfig) {
+        self.config = config;
+    }
+
+    /// Installs `controller`'s hooks to run after each later compile's
+    /// parse, expansion, and analysis phases; replaces any controller
+    /// installed by a previous call.
+    pub fn set_controller(&mut self, controller: Controller) {
+        *self.controller.lock().unwrap() = controller;
+    }
+
+    /// Removes any previously installed `Controller`.
+    pub fn clear_controller(&mut self) {
+        *self.controller.lock().unwrap() = Controller::default();
+    }
+
+    /// Begins a staged compilation of `input`, returning a `Queries` handle
+    /// whose `.analysis(..)` and `.module()` accessors lazily compute and
+    /// cache the parse, expansion, and translation stages needed to answer
+    /// them. Calling both accessors on the same handle only parses and
+    /// expands the crate once, unless a job from another handle runs in
+    /// between and replaces the cache. Every `Queries` submits its jobs to the
+    /// same persistent worker thread, so it also reuses the `Session` an
+    /// earlier, unrelated compile already built, as long as the
+    /// `CompileConfig` hasn't changed since.
+    pub fn compile<T>(&self, input: T) -> Queries where T: IntoInput {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        Queries::new(self.jobs.clone(), id, input.into_input(), self.config.clone())
+    }
+
     /// Compile a module and add it to the execution engine.
     /// If the module fails to compile, errors will be printed to `stderr`
     /// and `None` will be returned. Otherwise, the module is returned.
@@ -119,8 +280,7 @@ impl ExecutionEngine {
             where T: IntoInput {
         debug!("compiling module");
 
-        let (llmod, deps) = match compile_input(input.into_input(),
-                self.sysroot.clone(), self.lib_paths.clone()) {
+        let (llmod, deps) = match self.compile(input).module() {
             Some(r) => r,
             None => return None,
         };
@@ -162,15 +322,22 @@ impl ExecutionEngine {
     pub fn with_analysis<F, R, T>(&self, input: T, f: F) -> Option<R>
             where F: Send + 'static, R: Send + 'static, T: IntoInput,
             F: for<'a, 'gcx, 'tcx> FnOnce(&Crate, &ty::TyCtxt<'a, 'gcx, 'tcx>, ty::CrateAnalysis) -> R {
-        with_analysis(f, input.into_input(),
-            self.sysroot.clone(), self.lib_paths.clone())
+        self.compile(input).analysis(f)
+    }
+
+    /// Compiles `input` and returns its `kind` representation as text,
+    /// powering REPL commands like `:llvm`, `:asm`, and `:mir` without
+    /// shelling out to `rustc`.
+    pub fn emit<T>(&self, input: T, kind: EmitKind) -> Option<String> where T: IntoInput {
+        self.compile(input).emit(kind)
     }
 
     /// Searches for the named function in the set of loaded modules,
     /// beginning with the most recently added module.
     /// If the function is found, a raw pointer is returned.
-    /// If the function is not found, `None` is returned.
-    pub fn get_function(&mut self, name: &str) -> Option<*const ()> {
+    /// If the function is not found, `Err` carries the closest-matching
+    /// function name, if any is close enough to plausibly be a typo.
+    pub fn get_function(&mut self, name: &str) -> Result<*const (), Option<String>> {
         let s = CString::new(name.as_bytes()).unwrap();
 
         for m in self.modules.iter().rev() {
@@ -181,18 +348,19 @@ impl ExecutionEngine {
 
                 assert!(!fp.is_null());
 
-                return Some(fp as *const ());
+                return Ok(fp as *const ());
             }
         }
 
-        None
+        Err(find_best_match(name, self.function_names()))
     }
 
     /// Searches for the named global in the set of loaded modules,
     /// beginning with the most recently added module.
     /// If the global is found, a raw pointer is returned.
-    /// If the global is not found, `None` is returned.
-    pub fn get_global(&mut self, name: &str) -> Option<*const ()> {
+    /// If the global is not found, `Err` carries the closest-matching
+    /// global name, if any is close enough to plausibly be a typo.
+    pub fn get_global(&mut self, name: &str) -> Result<*const (), Option<String>> {
         let s = CString::new(name.as_bytes()).unwrap();
 
         for m in self.modules.iter().rev() {
@@ -203,11 +371,49 @@ impl ExecutionEngine {
 
                 assert!(!gp.is_null());
 
-                return Some(gp as *const ());
+                return Ok(gp as *const ());
+            }
+        }
+
+        Err(find_best_match(name, self.global_names()))
+    }
+
+    /// Lists the names of every function in `self.modules`, definitions
+    /// and external declarations alike, most-recently-added module first. Used for did-you-mean suggestions
+    /// and is independently useful for tab-completion.
+    pub fn function_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+
+        for m in self.modules.iter().rev() {
+            let mut f = unsafe { llvm::LLVMGetFirstFunction(*m) };
+
+            while !f.is_null() {
+                names.push(unsafe { value_name(f) });
+                f = unsafe { llvm::LLVMGetNextFunction(f) };
             }
         }
 
-        None
+        names
+    }
+
+    /// Lists the names of every global variable in `self.modules`,
+    /// definitions and external declarations alike,
+    /// most-recently-added module first. Used for
+    /// did-you-mean suggestions and is independently useful for
+    /// tab-completion.
+    pub fn global_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+
+        for m in self.modules.iter().rev() {
+            let mut g = unsafe { llvm::LLVMGetFirstGlobal(*m) };
+
+            while !g.is_null() {
+                names.push(unsafe { value_name(g) });
+                g = unsafe { llvm::LLVMGetNextGlobal(g) };
+            }
+        }
+
+        names
     }
 
     /// Loads all dependencies of compiled code.
@@ -239,6 +445,53 @@ impl Drop for ExecutionEngine {
     }
 }
 
+/// Reads the name of an LLVM function or global value.
+unsafe fn value_name(v: llvm::ValueRef) -> String {
+    CStr::from_ptr(llvm::LLVMGetValueName(v)).to_string_lossy().into_owned()
+}
+
+/// Finds the name in `candidates` closest to `name` by Levenshtein edit
+/// distance, the same technique rustc's own `find_best_match_for_name`
+/// uses to suggest a fix for a typo'd identifier. Returns `None` if
+/// nothing is within `max(name.len() / 3, 1)` edits, the point past which
+/// a suggestion is more likely to be noise than a genuine typo.
+fn find_best_match<I>(name: &str, candidates: I) -> Option<String>
+        where I: IntoIterator<Item = String> {
+    let max_dist = cmp::max(name.len() / 3, 1);
+
+    candidates.into_iter()
+        .map(|c| (levenshtein(name, &c), c))
+        .filter(|&(dist, _)| dist <= max_dist)
+        .min_by_key(|&(dist, _)| dist)
+        .map(|(_, c)| c)
+}
+
+/// Computes the Levenshtein edit distance between `a` and `b`.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+
+    for i in 0..a.len() + 1 {
+        d[i][0] = i;
+    }
+    for j in 0..b.len() + 1 {
+        d[0][j] = j;
+    }
+
+    for i in 1..a.len() + 1 {
+        for j in 1..b.len() + 1 {
+            let cost = if a[i - 1] == b[j - 1] { 0 } else { 1 };
+
+            d[i][j] = cmp::min(d[i - 1][j] + 1,
+                cmp::min(d[i][j - 1] + 1, d[i - 1][j - 1] + cost));
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
 /// Returns last error from LLVM wrapper code.
 fn llvm_error() -> String {
     String::from_utf8_lossy(
@@ -264,7 +517,7 @@ fn get_sysroot() -> PathBuf {
     PathBuf::from(path)
 }
 
-fn build_exec_options(sysroot: PathBuf, libs: Vec<String>) -> Options {
+fn build_exec_options(sysroot: PathBuf, libs: Vec<String>, config: &CompileConfig) -> Options {
     let mut opts = basic_options();
 
     // librustc derives sysroot from the executable name.
@@ -276,8 +529,11 @@ fn build_exec_options(sysroot: PathBuf, libs: Vec<String>) -> Options {
             ErrorOutputType::HumanReadable(errors::ColorConfig::Auto));
     }
 
-    // Prefer faster build times
-    opts.optimize = OptLevel::No;
+    opts.optimize = config.opt_level;
+    opts.debug_assertions = config.debug_assertions;
+    opts.cg.target_cpu = config.target_cpu.clone();
+    opts.cg.target_feature = config.target_features.clone();
+    opts.cfg.extend(parse_cfgspecs(config.extra_cfg.clone()));
 
     // Don't require a `main` function
     opts.crate_types = vec![config::CrateTypeDylib];
@@ -288,6 +544,27 @@ fn build_exec_options(sysroot: PathBuf, libs: Vec<String>) -> Options {
     opts
 }
 
+/// Adapts a `syntax::codemap::FileLoader` shared across every compile of an
+/// `ExecutionEngine` into the owned `Box<FileLoader + Send + Sync>`
+/// `build_session_with_file_loader` expects. This is rustc's own
+/// `FileLoader` trait, reused as-is rather than wrapped in a rusti-local
+/// one, so `abs_path` is delegated alongside `file_exists`/`read_file`.
+struct SharedFileLoader(Arc<FileLoader + Send + Sync>);
+
+impl FileLoader for SharedFileLoader {
+    fn file_exists(&self, path: &Path) -> bool {
+        self.0.file_exists(path)
+    }
+
+    fn abs_path(&self, path: &Path) -> Option<PathBuf> {
+        self.0.abs_path(path)
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.0.read_file(path)
+    }
+}
+
 struct SyncBuf(Arc<Mutex<Vec<u8>>>);
 
 impl Write for SyncBuf {
@@ -298,44 +575,384 @@ impl Write for SyncBuf {
     fn flush(&mut self) -> io::Result<()> { Ok(()) }
 }
 
-/// Compiles input up to phase 4, translation to LLVM.
+/// A staged, cache-backed compilation of a single input.
+///
+/// Returned by `ExecutionEngine::compile`. Parsing and macro expansion are
+/// expensive and only need to happen once per input, but type-check
+/// analysis and LLVM translation each need their own pass over the
+/// resulting HIR (the borrowed `TyCtxt` they build cannot outlive a single
+/// call). A `Queries` submits its jobs to the `ExecutionEngine`'s one
+/// persistent compilation worker thread, which caches the macro-expanded
+/// crate from the `ExpansionResult` against this `Queries`'s id, so
+/// `.analysis(..)` followed by `.module()` (or vice versa) on the same
+/// handle only parses and expands the crate once, provided no job from
+/// another `Queries` is serviced in between.
+pub struct Queries {
+    jobs: Sender<Job>,
+    id: usize,
+    /// This `Queries`'s input and the `CompileConfig` it should compile
+    /// with, resent with every job (not just the first): the worker
+    /// services jobs from every `Queries`/`add_module`/`with_analysis`/
+    /// `emit` call on one shared channel, so another job can flip the
+    /// worker's single cached `id` in between two calls on this handle.
+    /// Resending keeps the worker able to recover the right input no
+    /// matter what ran in between, instead of trusting stale state left
+    /// over from whichever job it serviced most recently.
+    input: Input,
+    config: CompileConfig,
+}
+
+/// The macro-expanded crate, cached on the compilation thread so phase 3
+/// can be re-run (once for `.analysis`, once for `.module`) without
+/// repeating phase 1 or phase 2.
+struct Expansion {
+    krate: Crate,
+    defs: ast_map::Definitions,
+    resolutions: driver::Resolutions,
+    analysis: ty::CrateAnalysis,
+    hir_forest: ast_map::Forest,
+}
+
+type AnalysisJob = Box<for<'a, 'gcx, 'tcx>
+    FnMut(&Crate, &ty::TyCtxt<'a, 'gcx, 'tcx>, ty::CrateAnalysis) -> Box<Any + Send> + Send>;
+
+enum Job {
+    Analysis(usize, Input, CompileConfig, AnalysisJob, Sender<Option<Box<Any + Send>>>),
+    Module(usize, Input, CompileConfig, Sender<Option<(usize, Deps)>>),
+    Emit(usize, Input, CompileConfig, EmitKind, Sender<Option<String>>),
+}
+
+impl Queries {
+    /// Wraps the given persistent worker's channel; submits no job and
+    /// spawns nothing itself, unlike the thread-per-compile design this
+    /// replaced.
+    fn new(jobs: Sender<Job>, id: usize, input: Input, config: CompileConfig) -> Queries {
+        Queries{jobs: jobs, id: id, input: input, config: config}
+    }
+
+    /// Compiles this input up to phase 3, type/region check analysis, and
+    /// calls the given closure with the borrowed type context and
+    /// resulting `CrateAnalysis`.
+    pub fn analysis<F, R>(&mut self, f: F) -> Option<R>
+            where F: Send + 'static, R: Send + 'static,
+            F: for<'a, 'gcx, 'tcx> FnOnce(&Crate, &ty::TyCtxt<'a, 'gcx, 'tcx>, ty::CrateAnalysis) -> R {
+        let mut f = Some(f);
+        let job: AnalysisJob = Box::new(move |krate, tcx, analysis| {
+            let f = f.take().expect("analysis job run more than once");
+            Box::new(f(krate, tcx, analysis)) as Box<Any + Send>
+        });
+
+        let (tx, rx) = mpsc::channel();
+
+        if self.jobs.send(Job::Analysis(self.id, self.input.clone(), self.config.clone(),
+                job, tx)).is_err() {
+            return None;
+        }
+
+        rx.recv().ok().and_then(|r| r)
+            .map(|b| *b.downcast::<R>().expect("analysis job returned wrong type"))
+    }
+
+    /// Compiles this input up to phase 4, translation to LLVM, caching and
+    /// returning the LLVM `ModuleRef` and a series of paths to dynamic
+    /// libraries for crates used in the given input.
+    pub fn module(&mut self) -> Option<(llvm::ModuleRef, Deps)> {
+        let (tx, rx) = mpsc::channel();
+
+        if self.jobs.send(Job::Module(self.id, self.input.clone(), self.config.clone(),
+                tx)).is_err() {
+            return None;
+        }
+
+        rx.recv().ok().and_then(|r| r).map(|(modp, deps)| (modp as llvm::ModuleRef, deps))
+    }
+
+    /// Compiles this input and returns its `kind` representation as text:
+    /// unoptimized LLVM IR, target assembly, or pretty-printed MIR.
+    pub fn emit(&mut self, kind: EmitKind) -> Option<String> {
+        let (tx, rx) = mpsc::channel();
+
+        if self.jobs.send(Job::Emit(self.id, self.input.clone(), self.config.clone(),
+                kind, tx)).is_err() {
+            return None;
+        }
+
+        rx.recv().ok().and_then(|r| r)
+    }
+}
+
+/// Spawns the persistent compilation worker thread an `ExecutionEngine`
+/// submits every later `compile`'s jobs to, so the `Session`/`CStore` it
+/// builds -- and the `std`/`extern crate` metadata they load -- outlive
+/// any single compiled snippet instead of being torn down and rebuilt on
+/// every keystroke.
+fn spawn_worker(sysroot: PathBuf, libs: Vec<String>,
+        file_loader: Option<Arc<FileLoader + Send + Sync>>,
+        controller: Arc<Mutex<Controller>>) -> Sender<Job> {
+    let (tx, rx) = mpsc::channel();
+
+    Builder::new().name("compile_input".to_owned()).spawn(move || {
+        run_worker(sysroot, libs, file_loader, controller, rx);
+    }).unwrap();
+
+    tx
+}
+
+/// Runs on the thread spawned by `spawn_worker` for as long as the
+/// `ExecutionEngine` that created it lives, servicing `Job`s sent by every
+/// `Queries` it has handed out.
+fn run_worker(sysroot: PathBuf, libs: Vec<String>,
+        file_loader: Option<Arc<FileLoader + Send + Sync>>,
+        controller: Arc<Mutex<Controller>>, rx: Receiver<Job>) {
+    driver::reset_thread_local_state();
+
+    let data = Arc::new(Mutex::new(Vec::new()));
+    if !log_enabled!(::log::LogLevel::Debug) {
+        io::set_panic(Box::new(SyncBuf(data.clone())));
+    }
+
+    let crate_name = "repl";
+
+    // The `Session`/`CStore`/`DepGraph` for the `CompileConfig` currently
+    // in use; rebuilt only when a job's config differs from it.
+    let mut session: Option<(Session, Rc<CStore>, DepGraph, CompileConfig)> = None;
+    let mut current_id: Option<usize> = None;
+    let mut input: Option<Input> = None;
+    let mut expansion: Option<Expansion> = None;
+    let mut module: Option<(usize, Deps)> = None;
+
+    for job in rx.iter() {
+        match job {
+            Job::Analysis(id, job_input, config, mut run, tx) => {
+                if prepare_worker_state(&mut session, &mut current_id, &mut input,
+                        &mut expansion, &mut module,
+                        &sysroot, &libs, &file_loader, &data, id, job_input, config).is_err() {
+                    let _ = tx.send(None);
+                    continue;
+                }
+
+                let (sess, cstore) = borrow_session(&session);
+                let input = input.as_ref().unwrap();
+
+                let r = guarded(&data, AssertUnwindSafe(|| {
+                    if ensure_expansion(sess, cstore, input, crate_name,
+                            &controller, &mut expansion).is_err() {
+                        return None;
+                    }
+
+                    let expansion = expansion.as_ref().unwrap();
+                    let arenas = ty::CtxtArenas::new();
+                    let mut hir_forest = expansion.hir_forest.clone();
+                    let ast_map = ast_map::map_crate(&mut hir_forest, expansion.defs.clone());
+
+                    check_compile(|| {
+                        driver::phase_3_run_analysis_passes(
+                            sess, ast_map, expansion.analysis.clone(),
+                            expansion.resolutions.clone(), &arenas, crate_name,
+                            |tcx, _mir_map, analysis, _| {
+                                let _ignore = tcx.dep_graph.in_ignore();
+
+                                if let Compilation::Stop = run_after_analysis(&controller, &tcx) {
+                                    return None;
+                                }
+
+                                Some(run(&expansion.krate, &tcx, analysis))
+                            })
+                    })
+                })).and_then(|r| r);
+
+                let _ = tx.send(r);
+            }
+            Job::Module(id, job_input, config, tx) => {
+                if prepare_worker_state(&mut session, &mut current_id, &mut input,
+                        &mut expansion, &mut module,
+                        &sysroot, &libs, &file_loader, &data, id, job_input, config).is_err() {
+                    let _ = tx.send(None);
+                    continue;
+                }
+
+                let r = match module {
+                    Some(ref cached) => Some(cached.clone()),
+                    None => {
+                        let (sess, cstore) = borrow_session(&session);
+                        let r = compile_module(sess, cstore, input.as_ref().unwrap(), crate_name,
+                            &controller, &data, &mut expansion);
+                        module = r.clone();
+                        r
+                    }
+                };
+
+                let _ = tx.send(r);
+            }
+            Job::Emit(id, job_input, config, kind, tx) => {
+                if prepare_worker_state(&mut session, &mut current_id, &mut input,
+                        &mut expansion, &mut module,
+                        &sysroot, &libs, &file_loader, &data, id, job_input, config).is_err() {
+                    let _ = tx.send(None);
+                    continue;
+                }
+
+                let (sess, cstore) = borrow_session(&session);
+                let in_input = input.as_ref().unwrap();
+
+                let r = match kind {
+                    EmitKind::Mir => run_mir_job(sess, cstore, in_input, crate_name,
+                        &controller, &data, &mut expansion),
+                    EmitKind::Llvm | EmitKind::Asm => {
+                        let m = match module {
+                            Some(ref cached) => Some(cached.clone()),
+                            None => {
+                                let r = compile_module(sess, cstore, in_input, crate_name,
+                                    &controller, &data, &mut expansion);
+                                module = r.clone();
+                                r
+                            }
+                        };
+
+                        m.and_then(|(modp, _)| {
+                            let llmod = modp as llvm::ModuleRef;
+
+                            if kind == EmitKind::Llvm {
+                                Some(print_module_ir(llmod))
+                            } else {
+                                write_module_asm(sess, llmod)
+                            }
+                        })
+                    }
+                };
+
+                let _ = tx.send(r);
+            }
+        }
+    }
+}
+
+/// Borrows the `Session`/`CStore` pair out of `session`, which must have
+/// already been populated by `prepare_worker_state`.
+fn borrow_session(session: &Option<(Session, Rc<CStore>, DepGraph, CompileConfig)>)
+        -> (&Session, &Rc<CStore>) {
+    let &(ref sess, ref cstore, _, _) = session.as_ref().unwrap();
+    (sess, cstore)
+}
+
+/// Advances the worker to service a job for `id`, re-sent `input`, and
+/// re-sent `config`. Clears the expansion/module caches whenever
+/// `id` differs from the one the worker last serviced -- a new `Queries`,
+/// hence a new snippet.
+///
+/// Every job a `Queries` sends carries its own `input`/`config` rather
+/// than relying on the worker to still have them cached: this is the
+/// single set of `input`/`expansion`/`module` slots shared by
+/// every `Queries` the `ExecutionEngine` has handed out, so another job
+/// serviced in between two calls on the same handle can (and does) flip
+/// `current_id` and overwrite them for a different snippet. Re-sending
+/// means that when this job's `id` turns out to be the one the worker
+/// last serviced, `input` is simply refreshed to the (identical) value
+/// already there, and when it isn't, the caches are reset to `None` here
+/// and recomputed from the correct, freshly supplied `input` below --
+/// never silently reused from whatever the last job happened to leave
+/// behind.
+///
+/// Rebuilds the `Session`/`CStore`/`DepGraph` only when `config` differs
+/// from the one already running; otherwise the existing `Session` (and
+/// the crate metadata it has already loaded) is reused as-is, which is
+/// the entire point of a persistent worker.
 ///
-/// Returns the LLVM `ModuleRef` and a series of paths to dynamic libraries
-/// for crates used in the given input.
-fn compile_input(input: Input, sysroot: PathBuf, libs: Vec<String>)
-        -> Option<(llvm::ModuleRef, Deps)> {
-    let r = monitor(move || {
+/// The rebuild runs under `guarded` -- `target_cpu`/`extra_cfg` in a
+/// `CompileConfig` are caller-supplied strings fed straight into rustc's
+/// `Options`, so a panic while building the new `Session` is plausible
+/// and user-triggerable. Without catching it here, it would escape this
+/// single persistent worker thread and silently kill compilation for
+/// every later job. On failure `session` is left as it was and `Err(())`
+/// is returned so the caller can fail just this one job instead.
+fn prepare_worker_state(
+        session: &mut Option<(Session, Rc<CStore>, DepGraph, CompileConfig)>,
+        current_id: &mut Option<usize>, input: &mut Option<Input>,
+        expansion: &mut Option<Expansion>,
+        module: &mut Option<(usize, Deps)>,
+        sysroot: &PathBuf, libs: &[String],
+        file_loader: &Option<Arc<FileLoader + Send + Sync>>,
+        data: &Arc<Mutex<Vec<u8>>>,
+        id: usize, new_input: Input, config: CompileConfig) -> Result<(), ()> {
+    if *current_id != Some(id) {
+        *current_id = Some(id);
+        *expansion = None;
+        *module = None;
+    }
+
+    *input = Some(new_input);
+
+    let rebuild = match *session {
+        Some((_, _, _, ref current)) => *current != config,
+        None => true,
+    };
+
+    if !rebuild {
+        return Ok(());
+    }
+
+    let built = guarded(data, AssertUnwindSafe(|| {
+        // The original thread-per-compile design reset this before every
+        // `Session`, on a thread that was about to be torn down with it.
+        // This worker reuses its OS thread across many `Session`
+        // lifetimes, so the reset has to be repeated here on every
+        // rebuild instead, or interner/node-id state left over from the
+        // previous `Session` could leak into the new one.
         driver::reset_thread_local_state();
-        let opts = build_exec_options(sysroot, libs);
+
+        let opts = build_exec_options(sysroot.clone(), libs.to_vec(), &config);
         let dep_graph = DepGraph::new(opts.build_dep_graph());
         let cstore = Rc::new(CStore::new(&dep_graph));
-        let sess = build_session(opts, &dep_graph, None,
-            Registry::new(&rustc::DIAGNOSTICS), cstore.clone());
+        let sess = match *file_loader {
+            Some(ref loader) => build_session_with_file_loader(opts, &dep_graph, None,
+                Registry::new(&rustc::DIAGNOSTICS), cstore.clone(),
+                Box::new(SharedFileLoader(loader.clone()))),
+            None => build_session(opts, &dep_graph, None,
+                Registry::new(&rustc::DIAGNOSTICS), cstore.clone()),
+        };
         rustc_lint::register_builtins(&mut sess.lint_store.borrow_mut(), Some(&sess));
 
-        let cfg = build_configuration(&sess);
+        Some((sess, cstore, dep_graph))
+    }));
 
-        let id = "repl";
+    let (sess, cstore, dep_graph) = match built {
+        Some(built) => built,
+        None => return Err(()),
+    };
 
-        let krate = match driver::phase_1_parse_input(&sess, cfg, &input) {
-            Ok(krate) => krate,
-            Err(mut e) => {
-                e.emit();
-                return None;
-            }
-        };
+    *session = Some((sess, cstore, dep_graph, config));
+    *expansion = None;
+    *module = None;
 
-        check_compile(|| {
-            let driver::ExpansionResult{defs, analysis, resolutions, mut hir_forest, ..} =
-                try!(driver::phase_2_configure_and_expand(
-                    &sess, &cstore, krate, id, None, MakeGlobMap::No, |_| Ok(())));
+    Ok(())
+}
+
+/// Compiles `input` up to phase 4, translation to LLVM, returning the
+/// resulting `ModuleRef` (as a `usize`, since raw pointers are not `Send`)
+/// and the paths of any dynamic libraries it depends on. Shared by
+/// `Job::Module` and the LLVM IR/assembly cases of `Job::Emit`.
+fn compile_module(sess: &Session, cstore: &Rc<CStore>, input: &Input, id: &str,
+        controller: &Arc<Mutex<Controller>>, data: &Arc<Mutex<Vec<u8>>>,
+        expansion: &mut Option<Expansion>) -> Option<(usize, Deps)> {
+    guarded(data, AssertUnwindSafe(|| {
+        if ensure_expansion(sess, cstore, input, id, controller, expansion).is_err() {
+            return None;
+        }
 
-            let arenas = ty::CtxtArenas::new();
-            let ast_map = ast_map::map_crate(&mut hir_forest, defs);
+        let expansion = expansion.as_ref().unwrap();
+        let arenas = ty::CtxtArenas::new();
+        let mut hir_forest = expansion.hir_forest.clone();
+        let ast_map = ast_map::map_crate(&mut hir_forest, expansion.defs.clone());
 
+        check_compile(|| {
             driver::phase_3_run_analysis_passes(
-                &sess, ast_map, analysis, resolutions, &arenas, id,
+                sess, ast_map, expansion.analysis.clone(),
+                expansion.resolutions.clone(), &arenas, id,
                 |tcx, mir_map, analysis, _| {
+                    if let Compilation::Stop = run_after_analysis(controller, &tcx) {
+                        return None;
+                    }
+
                     tcx.sess.abort_if_errors();
 
                     let trans = driver::phase_4_translate_to_llvm(
@@ -354,87 +971,234 @@ fn compile_input(input: Input, sysroot: PathBuf, libs: Vec<String>)
                     let llmod = trans.modules[0].llmod;
 
                     // Workaround because raw pointers do not impl Send
-                    let modp = llmod as usize;
+                    Some((llmod as usize, deps))
+                })
+        })
+    })).and_then(|r| r)
+}
+
+/// Compiles `input` up to analysis and pretty-prints the MIR for every
+/// item in the crate, for `EmitKind::Mir`.
+fn run_mir_job(sess: &Session, cstore: &Rc<CStore>, input: &Input, id: &str,
+        controller: &Arc<Mutex<Controller>>, data: &Arc<Mutex<Vec<u8>>>,
+        expansion: &mut Option<Expansion>) -> Option<String> {
+    guarded(data, AssertUnwindSafe(|| {
+        if ensure_expansion(sess, cstore, input, id, controller, expansion).is_err() {
+            return None;
+        }
 
-                    (modp, deps)
+        let expansion = expansion.as_ref().unwrap();
+        let arenas = ty::CtxtArenas::new();
+        let mut hir_forest = expansion.hir_forest.clone();
+        let ast_map = ast_map::map_crate(&mut hir_forest, expansion.defs.clone());
+
+        check_compile(|| {
+            driver::phase_3_run_analysis_passes(
+                sess, ast_map, expansion.analysis.clone(),
+                expansion.resolutions.clone(), &arenas, id,
+                |tcx, mir_map, _analysis, _| {
+                    if let Compilation::Stop = run_after_analysis(controller, &tcx) {
+                        return None;
+                    }
+
+                    tcx.sess.abort_if_errors();
+
+                    let mir_map = mir_map.expect("mir_map is None");
+                    let mut out = String::new();
+
+                    for (&def_id, mir) in mir_map.map.iter() {
+                        out.push_str(&format!("// MIR for {:?}\n{:#?}\n\n", def_id, mir));
+                    }
+
+                    Some(out)
                 })
         })
-    });
+    })).and_then(|r| r)
+}
 
-    r.and_then(|r| r).map(|(modp, deps)| (modp as llvm::ModuleRef, deps))
+/// Renders `llmod` as unoptimized LLVM IR text, via LLVM's
+/// `LLVMPrintModuleToString`.
+fn print_module_ir(llmod: llvm::ModuleRef) -> String {
+    unsafe {
+        let cstr = llvm::LLVMPrintModuleToString(llmod);
+        let ir = CStr::from_ptr(cstr).to_string_lossy().into_owned();
+        llvm::LLVMDisposeMessage(cstr);
+        ir
+    }
 }
 
-/// Compiles input up to phase 3, type/region check analysis, and calls
-/// the given closure with the borrowed type context and resulting `CrateAnalysis`.
-fn with_analysis<F, R>(f: F, input: Input, sysroot: PathBuf, libs: Vec<String>) -> Option<R>
-        where F: Send + 'static, R: Send + 'static,
-        F: for<'a, 'gcx, 'tcx> FnOnce(&Crate, &ty::TyCtxt<'a, 'gcx, 'tcx>, ty::CrateAnalysis) -> R {
-    monitor(move || {
-        driver::reset_thread_local_state();
-        let opts = build_exec_options(sysroot, libs);
-        let dep_graph = DepGraph::new(opts.build_dep_graph());
-        let cstore = Rc::new(CStore::new(&dep_graph));
-        let sess = build_session(opts, &dep_graph, None,
-            Registry::new(&rustc::DIAGNOSTICS), cstore.clone());
-        rustc_lint::register_builtins(&mut sess.lint_store.borrow_mut(), Some(&sess));
+/// Renders `llmod` as target assembly. The LLVM codegen backend can only
+/// emit to a file, so this writes to a temporary file and reads the
+/// result back.
+fn write_module_asm(sess: &Session, llmod: llvm::ModuleRef) -> Option<String> {
+    let triple = CString::new(sess.target.target.llvm_target.clone()).unwrap();
+    let cpu = CString::new(sess.opts.cg.target_cpu.clone().unwrap_or_default()).unwrap();
+    let features = CString::new(sess.target.target.options.features.clone()).unwrap();
 
-        let cfg = build_configuration(&sess);
+    let tm = unsafe {
+        llvm::LLVMRustCreateTargetMachine(triple.as_ptr(), cpu.as_ptr(), features.as_ptr())
+    };
 
-        let id = "repl";
+    if tm.is_null() {
+        return None;
+    }
 
-        let krate = match driver::phase_1_parse_input(&sess, cfg, &input) {
-            Ok(krate) => krate,
-            Err(mut e) => {
-                e.emit();
-                return None;
-            }
+    let path = create_unique_temp_path("rusti-emit", "s");
+
+    let asm = path.as_ref().and_then(|path| {
+        let cpath = CString::new(path.to_str().unwrap()).unwrap();
+        let wrote = unsafe { llvm::LLVMRustWriteOutputFile(tm, llmod, cpath.as_ptr()) };
+
+        if !wrote {
+            return None;
+        }
+
+        let mut file = match File::open(path) {
+            Ok(f) => f,
+            Err(_) => return None,
         };
 
-        check_compile(|| {
-            let driver::ExpansionResult{defs, analysis, resolutions, mut hir_forest,
-                    expanded_crate: krate} =
-                try!(driver::phase_2_configure_and_expand(
-                    &sess, &cstore, krate, id, None, MakeGlobMap::No, |_| Ok(())));
+        let mut asm = String::new();
+        match file.read_to_string(&mut asm) {
+            Ok(_) => Some(asm),
+            Err(_) => None,
+        }
+    });
 
-            let arenas = ty::CtxtArenas::new();
-            let ast_map = ast_map::map_crate(&mut hir_forest, defs);
+    unsafe { llvm::LLVMRustDisposeTargetMachine(tm) };
 
-            driver::phase_3_run_analysis_passes(
-                &sess, ast_map, analysis, resolutions, &arenas, id,
-                    |tcx, _mir_map, analysis, _| {
-                        let _ignore = tcx.dep_graph.in_ignore();
-                        f(&krate, &tcx, analysis)
-                    })
-        })
-    }).and_then(|r| r)
+    if let Some(ref path) = path {
+        let _ = fs::remove_file(path);
+    }
+
+    asm
 }
 
-fn check_compile<F, R>(f: F) -> Option<R> where F: FnOnce() -> Result<R, usize> {
-    f().ok()
+/// Claims a fresh, unpredictable path under the system temp directory,
+/// creating the file ourselves with `create_new` (the std equivalent of
+/// `O_EXCL`) so that a name collision -- including a symlink an attacker
+/// planted in advance at a guessable name -- makes this fail rather than
+/// be silently followed. `write_module_asm` needs a real path to hand to
+/// the LLVM backend, which only knows how to emit to a file by name, so
+/// this is the best a std-only implementation can do to close that race.
+fn create_unique_temp_path(prefix: &str, ext: &str) -> Option<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    for _ in 0..1000 {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos()).unwrap_or(0);
+        let n = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("{}-{:x}-{:x}.{}", prefix, nanos, n, ext));
+
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Some(path),
+            Err(_) => continue,
+        }
+    }
+
+    None
 }
 
-fn monitor<F, R>(f: F) -> Option<R>
-        where F: Send + 'static + FnOnce() -> R, R: Send + 'static {
-    let thread = Builder::new().name("compile_input".to_owned());
-    let data = Arc::new(Mutex::new(Vec::new()));
-    let sink = SyncBuf(data.clone());
+/// Parses and macro-expands `input` if it has not been already, caching
+/// the result in `expansion` so later calls skip straight to phase 3.
+/// Returns `Err` both on a genuine compile error and when a `Controller`
+/// hook asks to stop; either way, there is nothing further to compute.
+///
+/// Parsing and expansion always run back to back within a single call
+/// here, so there is no separate, reusable "parsed but not yet expanded"
+/// state worth caching across calls; only `expansion` is. It is not
+/// cached until the `after_expand` hook has confirmed
+/// `Compilation::Continue`, so a `Stop` is never silently skipped by a
+/// later call that finds the work already done.
+fn ensure_expansion(sess: &Session, cstore: &Rc<CStore>, input: &Input, id: &str,
+        controller: &Arc<Mutex<Controller>>,
+        expansion: &mut Option<Expansion>) -> Result<(), ()> {
+    if expansion.is_some() {
+        return Ok(());
+    }
 
-    let handle = thread.spawn(move || {
-        if !log_enabled!(::log::LogLevel::Debug) {
-            io::set_panic(Box::new(sink));
+    let cfg = build_configuration(sess);
+    let krate = match driver::phase_1_parse_input(sess, cfg, input) {
+        Ok(k) => k,
+        Err(mut e) => {
+            e.emit();
+            return Err(());
         }
-        f()
-    }).unwrap();
+    };
+
+    if let Compilation::Stop = run_after_parse(controller, &krate) {
+        return Err(());
+    }
+
+    let result = driver::phase_2_configure_and_expand(
+        sess, cstore, krate, id, None, MakeGlobMap::No, |_| Ok(()));
+
+    match result {
+        Ok(driver::ExpansionResult{defs, analysis, resolutions, hir_forest, expanded_crate}) => {
+            match run_after_expand(controller, &expanded_crate) {
+                Compilation::Continue => {
+                    *expansion = Some(Expansion{
+                        krate: expanded_crate,
+                        defs: defs,
+                        resolutions: resolutions,
+                        analysis: analysis,
+                        hir_forest: hir_forest,
+                    });
+                    Ok(())
+                }
+                Compilation::Stop => Err(()),
+            }
+        }
+        Err(_) => Err(()),
+    }
+}
+
+/// Runs the installed `Controller`'s `after_parse` hook, if any.
+fn run_after_parse(controller: &Arc<Mutex<Controller>>, krate: &Crate) -> Compilation {
+    match controller.lock().unwrap().after_parse {
+        Some(ref mut hook) => hook(krate),
+        None => Compilation::Continue,
+    }
+}
 
-    match handle.join() {
-        Ok(r) => Some(r),
+/// Runs the installed `Controller`'s `after_expand` hook, if any.
+fn run_after_expand(controller: &Arc<Mutex<Controller>>, krate: &Crate) -> Compilation {
+    match controller.lock().unwrap().after_expand {
+        Some(ref mut hook) => hook(krate),
+        None => Compilation::Continue,
+    }
+}
+
+/// Runs the installed `Controller`'s `after_analysis` hook, if any.
+fn run_after_analysis<'a, 'gcx, 'tcx>(controller: &Arc<Mutex<Controller>>,
+        tcx: &ty::TyCtxt<'a, 'gcx, 'tcx>) -> Compilation {
+    match controller.lock().unwrap().after_analysis {
+        Some(ref mut hook) => hook(tcx),
+        None => Compilation::Continue,
+    }
+}
+
+/// Runs `f`, catching and reporting a compiler panic the way a freshly
+/// spawned and joined thread used to, without actually tearing down the
+/// (long-lived) compilation thread it runs on.
+fn guarded<F, R>(data: &Arc<Mutex<Vec<u8>>>, f: F) -> Option<R>
+        where F: FnOnce() -> Option<R> + panic::UnwindSafe {
+    // Start each job with an empty panic buffer, as a fresh thread had.
+    data.lock().unwrap().clear();
+
+    match panic::catch_unwind(f) {
+        Ok(r) => r,
         Err(e) => {
-            handle_compiler_panic(e, data);
+            handle_compiler_panic(e, data.clone());
             None
         }
     }
 }
 
+fn check_compile<F, R>(f: F) -> Option<R> where F: FnOnce() -> Result<R, usize> {
+    f().ok()
+}
+
 fn handle_compiler_panic(e: Box<Any + Send + 'static>, data: Arc<Mutex<Vec<u8>>>) {
     if !e.is::<errors::FatalError>() {
         if !e.is::<errors::ExplicitBug>() {